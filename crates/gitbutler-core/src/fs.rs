@@ -0,0 +1,295 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        Mutex,
+    },
+};
+
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher as NotifyWatcher};
+
+/// The subset of [`std::fs::Metadata`] that callers actually need, redefined here so
+/// that [`FakeFs`] can construct values without going through the OS.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    pub is_file: bool,
+    pub is_dir: bool,
+    pub len: u64,
+}
+
+/// A live filesystem watch, as returned by [`Fs::watch`].
+///
+/// Owns the underlying watcher (if any) so it isn't dropped out from under the
+/// caller, and exposes incoming events as a plain channel, matching the
+/// `std::sync::mpsc` style the rest of the watcher already uses.
+pub struct WatchHandle {
+    pub events: Receiver<notify::Result<Event>>,
+    /// Kept alive for as long as the watch should run; `None` for [`FakeFs`], which
+    /// has nothing to keep alive. Drop this (or the whole handle) to stop watching.
+    pub watcher: Option<RecommendedWatcher>,
+}
+
+/// Abstracts disk access so the watcher, [`crate::ops::state::OplogHandle`] and the
+/// storage layer can be driven deterministically in tests against [`FakeFs`], rather
+/// than always touching a real project checkout on disk.
+///
+/// Modeled on Zed's `fs` crate: a small, blocking trait with a [`RealFs`]
+/// implementation backed by `std::fs`/`notify`, and a [`FakeFs`] implementation
+/// backed by an in-memory tree of paths to bytes.
+pub trait Fs: Send + Sync {
+    fn read_to_string(&self, path: &Path) -> io::Result<String>;
+    /// Writes `contents` to `path`, replacing it atomically (write-to-temp-then-rename)
+    /// so readers never observe a half-written file.
+    fn write(&self, path: &Path, contents: &str) -> io::Result<()>;
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+    fn metadata(&self, path: &Path) -> io::Result<Metadata>;
+    fn exists(&self, path: &Path) -> bool;
+    fn is_file(&self, path: &Path) -> bool;
+    /// Atomically creates a file at `path` containing `contents`, failing with
+    /// `io::ErrorKind::AlreadyExists` if it's already there. Used to implement
+    /// advisory locks: only one caller can win the create, and `contents` lets the
+    /// winner record who holds the lock (e.g. a pid, to recognize a stale lock left
+    /// behind by a crash).
+    fn create_exclusive(&self, path: &Path, contents: &[u8]) -> io::Result<()>;
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+    /// Starts watching `path` recursively, returning a channel of raw `notify` events.
+    fn watch(&self, path: &Path) -> notify::Result<WatchHandle>;
+}
+
+/// The default [`Fs`], backed by `std::fs` and a real `notify` watcher.
+#[derive(Default)]
+pub struct RealFs;
+
+impl Fs for RealFs {
+    fn read_to_string(&self, path: &Path) -> io::Result<String> {
+        std::fs::read_to_string(path)
+    }
+
+    fn write(&self, path: &Path, contents: &str) -> io::Result<()> {
+        let tmp_path = path.with_file_name(format!(
+            "{}.tmp.{}",
+            path.file_name().and_then(|n| n.to_str()).unwrap_or("fs-write"),
+            std::process::id()
+        ));
+        std::fs::write(&tmp_path, contents)?;
+        std::fs::rename(&tmp_path, path)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        std::fs::create_dir_all(path)
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        let metadata = std::fs::metadata(path)?;
+        Ok(Metadata {
+            is_file: metadata.is_file(),
+            is_dir: metadata.is_dir(),
+            len: metadata.len(),
+        })
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        path.is_file()
+    }
+
+    fn create_exclusive(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        use std::io::Write;
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)?;
+        file.write_all(contents)?;
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        std::fs::remove_file(path)
+    }
+
+    fn watch(&self, path: &Path) -> notify::Result<WatchHandle> {
+        let (tx, rx) = channel();
+        let mut watcher = RecommendedWatcher::new(tx, notify::Config::default())?;
+        watcher.watch(path, RecursiveMode::Recursive)?;
+        Ok(WatchHandle {
+            events: rx,
+            watcher: Some(watcher),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+enum FakeEntry {
+    File(Vec<u8>),
+    Dir,
+}
+
+/// An in-memory [`Fs`] for tests: a tree of paths to bytes, with no real disk access.
+///
+/// Use [`FakeFs::emit`] to script `notify` events against paths registered through
+/// [`Fs::watch`], so the delta/CRDT pipeline and oplog head bookkeeping can be
+/// exercised deterministically.
+#[derive(Default)]
+pub struct FakeFs {
+    entries: Mutex<BTreeMap<PathBuf, FakeEntry>>,
+    watchers: Mutex<HashMap<PathBuf, Vec<Sender<notify::Result<Event>>>>>,
+}
+
+impl FakeFs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Seeds the fake tree with a file, creating parent directories as needed.
+    pub fn insert_file(&self, path: impl Into<PathBuf>, contents: impl Into<Vec<u8>>) {
+        let path = path.into();
+        let mut entries = self.entries.lock().unwrap();
+        for ancestor in path.ancestors().skip(1) {
+            entries.entry(ancestor.to_path_buf()).or_insert(FakeEntry::Dir);
+        }
+        entries.insert(path, FakeEntry::File(contents.into()));
+    }
+
+    /// Delivers a synthetic `notify` event to every watcher registered on an
+    /// ancestor of `path`, as if a real filesystem watch had produced it.
+    pub fn emit(&self, path: impl Into<PathBuf>, kind: notify::EventKind) {
+        let path = path.into();
+        let watchers = self.watchers.lock().unwrap();
+        for (root, senders) in watchers.iter() {
+            if path.starts_with(root) {
+                let event = Event::new(kind.clone()).add_path(path.clone());
+                for sender in senders {
+                    let _ = sender.send(Ok(event.clone()));
+                }
+            }
+        }
+    }
+}
+
+impl Fs for FakeFs {
+    fn read_to_string(&self, path: &Path) -> io::Result<String> {
+        match self.entries.lock().unwrap().get(path) {
+            Some(FakeEntry::File(bytes)) => String::from_utf8(bytes.clone())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            Some(FakeEntry::Dir) => Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("{} is a directory", path.display()),
+            )),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} does not exist", path.display()),
+            )),
+        }
+    }
+
+    fn write(&self, path: &Path, contents: &str) -> io::Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        for ancestor in path.ancestors().skip(1) {
+            entries.entry(ancestor.to_path_buf()).or_insert(FakeEntry::Dir);
+        }
+        entries.insert(path.to_path_buf(), FakeEntry::File(contents.as_bytes().to_vec()));
+        Ok(())
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        for ancestor in path.ancestors() {
+            entries.entry(ancestor.to_path_buf()).or_insert(FakeEntry::Dir);
+        }
+        Ok(())
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        match self.entries.lock().unwrap().get(path) {
+            Some(FakeEntry::File(bytes)) => Ok(Metadata {
+                is_file: true,
+                is_dir: false,
+                len: bytes.len() as u64,
+            }),
+            Some(FakeEntry::Dir) => Ok(Metadata {
+                is_file: false,
+                is_dir: true,
+                len: 0,
+            }),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} does not exist", path.display()),
+            )),
+        }
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.entries.lock().unwrap().contains_key(path)
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        matches!(self.entries.lock().unwrap().get(path), Some(FakeEntry::File(_)))
+    }
+
+    fn create_exclusive(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.contains_key(path) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already exists", path.display()),
+            ));
+        }
+        for ancestor in path.ancestors().skip(1) {
+            entries.entry(ancestor.to_path_buf()).or_insert(FakeEntry::Dir);
+        }
+        entries.insert(path.to_path_buf(), FakeEntry::File(contents.to_vec()));
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        match self.entries.lock().unwrap().remove(path) {
+            Some(_) => Ok(()),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} does not exist", path.display()),
+            )),
+        }
+    }
+
+    fn watch(&self, path: &Path) -> notify::Result<WatchHandle> {
+        let (tx, rx) = channel();
+        self.watchers
+            .lock()
+            .unwrap()
+            .entry(path.to_path_buf())
+            .or_default()
+            .push(tx);
+        Ok(WatchHandle {
+            events: rx,
+            watcher: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fake_fs_round_trips_writes() {
+        let fs = FakeFs::new();
+        fs.write(Path::new("/repo/file.txt"), "hello").unwrap();
+        assert_eq!(fs.read_to_string(Path::new("/repo/file.txt")).unwrap(), "hello");
+        assert!(fs.is_file(Path::new("/repo/file.txt")));
+        assert!(fs.exists(Path::new("/repo")));
+    }
+
+    #[test]
+    fn fake_fs_emits_scripted_events_to_watchers() {
+        let fs = FakeFs::new();
+        let handle = fs.watch(Path::new("/repo")).unwrap();
+        fs.emit(Path::new("/repo/file.txt"), notify::EventKind::Create(notify::event::CreateKind::File));
+        let event = handle.events.recv().unwrap().unwrap();
+        assert_eq!(event.paths, vec![PathBuf::from("/repo/file.txt")]);
+    }
+}
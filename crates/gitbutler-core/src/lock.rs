@@ -0,0 +1,128 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::fs::Fs;
+
+const RETRY_ATTEMPTS: u32 = 20;
+const RETRY_DELAY: Duration = Duration::from_millis(50);
+
+/// An advisory lock, held for the duration of a read-modify-write against its
+/// companion file.
+///
+/// Modeled on Mercurial's `try_with_lock_no_wait`: acquired via an exclusive
+/// `O_CREAT|O_EXCL`-style create of a sibling `.lock` file, and released on drop.
+/// Acquisition retries with bounded backoff if another process already holds it,
+/// rather than failing immediately or blocking forever. The lock file is seeded with
+/// the holder's pid so a stale lock left behind by a crashed process can be recognized
+/// and broken, the same way Mercurial's implementation does.
+pub struct FileLock {
+    fs: Arc<dyn Fs>,
+    path: PathBuf,
+}
+
+impl FileLock {
+    /// Acquires the lock for `path` (e.g. `oplog.toml` -> `oplog.toml.lock`),
+    /// retrying with backoff for up to a second before giving up. If the lock is held
+    /// by a pid that's no longer running, it's broken immediately rather than retried
+    /// against, since its holder will never release it.
+    pub fn acquire(fs: Arc<dyn Fs>, path: &Path) -> Result<Self> {
+        let lock_path = PathBuf::from(format!("{}.lock", path.display()));
+        let mut broke_stale_lock = false;
+        for attempt in 0..RETRY_ATTEMPTS {
+            match fs.create_exclusive(&lock_path, std::process::id().to_string().as_bytes()) {
+                Ok(()) => return Ok(Self { fs, path: lock_path }),
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                    if !broke_stale_lock && is_stale(fs.as_ref(), &lock_path) {
+                        let _ = fs.remove_file(&lock_path);
+                        broke_stale_lock = true;
+                        continue;
+                    }
+                    if attempt + 1 == RETRY_ATTEMPTS {
+                        return Err(anyhow!(
+                            "timed out waiting for lock {}: held by another process",
+                            lock_path.display()
+                        ));
+                    }
+                    thread::sleep(RETRY_DELAY);
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        unreachable!("loop above always returns")
+    }
+}
+
+/// Whether the pid recorded in `lock_path` belongs to a process that's no longer
+/// running. Errs on the side of "not stale" (keeps retrying) if the lock file can't be
+/// read or doesn't contain a pid, since that's also what a lock held by a well-behaved
+/// concurrent writer looks like mid-write.
+fn is_stale(fs: &dyn Fs, lock_path: &Path) -> bool {
+    let Ok(contents) = fs.read_to_string(lock_path) else {
+        return false;
+    };
+    let Ok(pid) = contents.trim().parse::<u32>() else {
+        return false;
+    };
+    !process_is_alive(pid)
+}
+
+#[cfg(unix)]
+fn process_is_alive(pid: u32) -> bool {
+    // signal 0 sends nothing; it only checks whether we could signal the process,
+    // which fails with ESRCH if it doesn't exist and EPERM if it exists but isn't ours
+    let result = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    result == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn process_is_alive(_pid: u32) -> bool {
+    // no portable liveness check off unix; assume alive so we fall back to the normal
+    // retry/timeout behavior instead of ever breaking a lock we can't actually verify
+    true
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        if let Err(e) = self.fs.remove_file(&self.path) {
+            log::warn!("failed to release lock {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::FakeFs;
+
+    #[test]
+    fn lock_can_be_acquired_again_once_the_first_is_dropped() {
+        let fs = Arc::new(FakeFs::new());
+        let path = Path::new("/repo/oplog.toml");
+
+        let lock = FileLock::acquire(fs.clone(), path).unwrap();
+        assert!(fs.is_file(Path::new("/repo/oplog.toml.lock")));
+
+        drop(lock);
+        let _lock = FileLock::acquire(fs.clone(), path).unwrap();
+    }
+
+    #[test]
+    fn acquire_breaks_a_stale_lock_left_by_a_dead_pid() {
+        let fs = Arc::new(FakeFs::new());
+        let path = Path::new("/repo/oplog.toml");
+        let lock_path = Path::new("/repo/oplog.toml.lock");
+
+        // a pid this unlikely to be running simulates a lock file left behind by a
+        // process that crashed before it could release the lock on drop
+        fs.write(lock_path, "999999999").unwrap();
+
+        let _lock = FileLock::acquire(fs.clone(), path).unwrap();
+        assert!(fs.is_file(lock_path));
+    }
+}
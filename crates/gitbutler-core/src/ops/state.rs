@@ -1,35 +1,66 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use std::{
-    fs::File,
-    io::Read,
     path::{Path, PathBuf},
+    sync::Arc,
 };
 
+use git2::{IndexAddOption, Oid, Repository};
 use serde::{Deserialize, Serialize};
 
-/// This tracks the head of the oplog, persisted in oplog.toml.  
+use crate::fs::Fs;
+use crate::lock::FileLock;
+
+/// This tracks the head of the oplog, persisted in oplog.toml.
 #[derive(Serialize, Deserialize, Debug, Default)]
 pub struct Oplog {
     /// This is the sha of the last oplog commit
     pub head_sha: Option<String>,
 }
 
+/// A single entry in the oplog, as returned by [`OplogHandle::operations`].
+///
+/// Mirrors the fields of the underlying oplog commit, so the frontend can render an
+/// undo timeline without needing to understand git internals.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OperationEntry {
+    /// The sha of the oplog commit recording this snapshot.
+    pub sha: String,
+    /// The sha of the previous snapshot in the chain, if any.
+    pub parent_sha: Option<String>,
+    /// Seconds since the epoch, as recorded on the oplog commit.
+    pub timestamp: i64,
+    /// The description passed to [`OplogHandle::snapshot`].
+    pub description: String,
+}
+
 pub struct OplogHandle {
     /// The path to the file containing the oplog head state.
     file_path: PathBuf,
+    /// Disk access, abstracted so tests can drive this against a [`crate::fs::FakeFs`].
+    fs: Arc<dyn Fs>,
 }
 
 impl OplogHandle {
     /// Creates a new concurrency-safe handle to the state of the oplog.
-    pub fn new(base_path: &Path) -> Self {
+    pub fn new(base_path: &Path, fs: Arc<dyn Fs>) -> Self {
         let file_path = base_path.join("oplog.toml");
-        Self { file_path }
+        Self { file_path, fs }
     }
 
     /// Persists the oplog head for the given repository.
     ///
-    /// Errors if the file cannot be read or written.
+    /// Errors if the file cannot be read or written. Holds an advisory lock for the
+    /// whole read-modify-write so two GitButler processes racing on this file can't
+    /// lose an update or leave it half-written.
     pub fn set_oplog_head(&self, sha: String) -> Result<()> {
+        let _lock = FileLock::acquire(self.fs.clone(), &self.file_path)?;
+        self.set_oplog_head_locked(sha)
+    }
+
+    /// Same as [`OplogHandle::set_oplog_head`], but assumes the caller already holds
+    /// the lock (e.g. [`OplogHandle::snapshot`], which reads the previous head under
+    /// the same lock it writes the new one under).
+    fn set_oplog_head_locked(&self, sha: String) -> Result<()> {
         let mut oplog = self.read_file()?;
         oplog.head_sha = Some(sha);
         self.write_file(&oplog)?;
@@ -44,16 +75,105 @@ impl OplogHandle {
         Ok(oplog.head_sha)
     }
 
+    /// Snapshots the current working tree (including any captured session deltas)
+    /// into a tree object, and commits it with the previous oplog head as parent.
+    ///
+    /// Advances `head_sha` to the new commit, so [`OplogHandle::operations`] grows a
+    /// real chain rather than a single dangling pointer. Returns the new commit's sha.
+    ///
+    /// Holds one advisory lock across reading the previous head, committing against
+    /// it, and writing the new head, so two processes snapshotting concurrently can't
+    /// both commit against the same parent and race on which one becomes reachable.
+    pub fn snapshot(&self, repo: &Repository, description: &str) -> Result<Oid> {
+        let _lock = FileLock::acquire(self.fs.clone(), &self.file_path)?;
+
+        let mut index = repo.index().context("failed to open the repository index")?;
+        index
+            .add_all(["*"], IndexAddOption::DEFAULT, None)
+            .context("failed to stage the working tree for a snapshot")?;
+        stage_session_state(repo, &mut index)
+            .context("failed to stage in-flight session deltas for a snapshot")?;
+        let tree_id = index.write_tree().context("failed to write snapshot tree")?;
+        let tree = repo.find_tree(tree_id)?;
+
+        let parent_sha = self.read_file()?.head_sha;
+        let parents = parent_sha
+            .as_deref()
+            .map(|sha| repo.find_commit(sha.parse()?))
+            .transpose()
+            .context("failed to look up the previous oplog head")?;
+        let parents: Vec<_> = parents.iter().collect();
+
+        let signature = repo.signature().or_else(|_| {
+            git2::Signature::now("GitButler", "gitbutler@gitbutler.com")
+        })?;
+        let oid = repo.commit(
+            None,
+            &signature,
+            &signature,
+            description,
+            &tree,
+            &parents,
+        )?;
+
+        self.set_oplog_head_locked(oid.to_string())?;
+        Ok(oid)
+    }
+
+    /// Walks the oplog chain from `head_sha` back to its root, returning one
+    /// [`OperationEntry`] per snapshot, most recent first.
+    pub fn operations(&self, repo: &Repository) -> Result<Vec<OperationEntry>> {
+        let mut entries = Vec::new();
+        let Some(head_sha) = self.get_oplog_head()? else {
+            return Ok(entries);
+        };
+
+        let mut next = Some(head_sha);
+        while let Some(sha) = next {
+            let commit = repo.find_commit(sha.parse()?)?;
+            let parent_sha = commit.parent_id(0).ok().map(|oid| oid.to_string());
+            entries.push(OperationEntry {
+                sha,
+                parent_sha: parent_sha.clone(),
+                timestamp: commit.time().seconds(),
+                description: commit.message().unwrap_or_default().to_string(),
+            });
+            next = parent_sha;
+        }
+
+        Ok(entries)
+    }
+
+    /// Resets the working directory back to the tree recorded by a prior snapshot,
+    /// giving users an undo timeline across editing sessions.
+    ///
+    /// Holds the same advisory lock [`OplogHandle::snapshot`] uses for the whole
+    /// checkout-and-advance-head sequence, so a concurrent snapshot can't advance
+    /// past the restore target while it's in flight.
+    pub fn restore(&self, repo: &Repository, sha: &str) -> Result<()> {
+        let _lock = FileLock::acquire(self.fs.clone(), &self.file_path)?;
+
+        let commit = repo
+            .find_commit(sha.parse()?)
+            .with_context(|| format!("{sha} is not a known oplog snapshot"))?;
+        let tree = commit.tree()?;
+
+        let mut checkout = git2::build::CheckoutBuilder::new();
+        checkout.force().remove_untracked(true);
+        repo.checkout_tree(tree.as_object(), Some(&mut checkout))?;
+
+        self.set_oplog_head_locked(sha.to_string())?;
+        Ok(())
+    }
+
     /// Reads and parses the state file.
     ///
     /// If the file does not exist, it will be created.
     fn read_file(&self) -> Result<Oplog> {
-        if !self.file_path.exists() {
+        if !self.fs.exists(&self.file_path) {
             return Ok(Oplog::default());
         }
-        let mut file: File = File::open(self.file_path.as_path())?;
-        let mut contents = String::new();
-        file.read_to_string(&mut contents)?;
+        let contents = self.fs.read_to_string(&self.file_path)?;
         let oplog: Oplog =
             toml::from_str(&contents).map_err(|e| crate::reader::Error::ParseError {
                 path: self.file_path.clone(),
@@ -63,11 +183,139 @@ impl OplogHandle {
     }
 
     fn write_file(&self, oplog: &Oplog) -> anyhow::Result<()> {
-        write(self.file_path.as_path(), oplog)
+        let contents = toml::to_string(oplog)?;
+        self.fs.write(&self.file_path, &contents)?;
+        Ok(())
     }
 }
 
-fn write<P: AsRef<Path>>(file_path: P, oplog: &Oplog) -> anyhow::Result<()> {
-    let contents = toml::to_string(&oplog)?;
-    crate::fs::write(file_path, contents)
+// Captured CRDT session deltas live under `<gitdir>/gb/session`, outside the working
+// directory `index.add_all` stages, so a snapshot of the workdir alone would miss any
+// in-flight edits that hadn't been flushed to a real commit yet. Fold those files into
+// the index too (under a synthetic `gb-session/` tree path) so the snapshot tree
+// records them. `restore` checks that path out like the rest of the tree, as a
+// `gb-session/` directory at the root of the working directory; it does not copy the
+// files back under `<gitdir>/gb/session`.
+//
+// This reads straight off disk with `std::fs` rather than through `self.fs`: unlike
+// `oplog.toml`, session state is only ever produced by a real `git2::Repository`'s
+// gitdir, so there's no [`crate::fs::FakeFs`] scenario that needs to fake it out.
+fn stage_session_state(repo: &Repository, index: &mut git2::Index) -> Result<()> {
+    let session_path = repo.path().join("gb/session");
+    if !session_path.is_dir() {
+        return Ok(());
+    }
+
+    let mut files = Vec::new();
+    collect_files(&session_path, &mut files)?;
+
+    for path in files {
+        let relative_path = path
+            .strip_prefix(&session_path)
+            .expect("walked from session_path, so it must be a prefix");
+        let tree_path = Path::new("gb-session").join(relative_path);
+        let contents = std::fs::read(&path)
+            .with_context(|| format!("failed to read session state file {}", path.display()))?;
+
+        index
+            .add_frombuffer(
+                &git2::IndexEntry {
+                    ctime: git2::IndexTime::new(0, 0),
+                    mtime: git2::IndexTime::new(0, 0),
+                    dev: 0,
+                    ino: 0,
+                    mode: 0o100_644,
+                    uid: 0,
+                    gid: 0,
+                    file_size: contents.len() as u32,
+                    id: git2::Oid::zero(),
+                    flags: 0,
+                    flags_extended: 0,
+                    path: tree_path.to_string_lossy().into_owned().into_bytes(),
+                },
+                &contents,
+            )
+            .with_context(|| format!("failed to stage session state file {}", tree_path.display()))?;
+    }
+
+    Ok(())
+}
+
+fn collect_files(dir: &Path, out: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in std::fs::read_dir(dir).with_context(|| format!("failed to read {}", dir.display()))? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_files(&path, out)?;
+        } else {
+            out.push(path);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::FakeFs;
+    use std::fs;
+
+    // a real repository with one (uncommitted) file in its work tree, so `snapshot`/`restore` have actual
+    // git plumbing (index, trees, checkout) to exercise; the oplog head itself is
+    // still bookkept through `FakeFs`, independent of this checkout.
+    fn test_repo() -> (tempfile::TempDir, Repository) {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        fs::write(dir.path().join("file.txt"), "hello").unwrap();
+        (dir, repo)
+    }
+
+    #[test]
+    fn snapshot_operations_restore_round_trip() {
+        let (dir, repo) = test_repo();
+        let handle = OplogHandle::new(dir.path(), Arc::new(FakeFs::new()));
+
+        let first = handle.snapshot(&repo, "first snapshot").unwrap();
+
+        fs::write(dir.path().join("file.txt"), "changed").unwrap();
+        let second = handle.snapshot(&repo, "second snapshot").unwrap();
+
+        assert_eq!(handle.get_oplog_head().unwrap(), Some(second.to_string()));
+
+        let ops = handle.operations(&repo).unwrap();
+        assert_eq!(ops.len(), 2);
+        assert_eq!(ops[0].sha, second.to_string());
+        assert_eq!(ops[0].parent_sha, Some(first.to_string()));
+        assert_eq!(ops[0].description, "second snapshot");
+        assert_eq!(ops[1].sha, first.to_string());
+        assert_eq!(ops[1].parent_sha, None);
+        assert_eq!(ops[1].description, "first snapshot");
+
+        handle.restore(&repo, &first.to_string()).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(dir.path().join("file.txt")).unwrap(),
+            "hello"
+        );
+        assert_eq!(handle.get_oplog_head().unwrap(), Some(first.to_string()));
+    }
+
+    #[test]
+    fn snapshot_captures_in_flight_session_deltas() {
+        let (dir, repo) = test_repo();
+        let handle = OplogHandle::new(dir.path(), Arc::new(FakeFs::new()));
+
+        let session_deltas = repo.path().join("gb/session/deltas");
+        fs::create_dir_all(&session_deltas).unwrap();
+        fs::write(session_deltas.join("file.txt"), r#"[{"timestamp":1}]"#).unwrap();
+
+        let oid = handle.snapshot(&repo, "snapshot with session state").unwrap();
+
+        let commit = repo.find_commit(oid).unwrap();
+        let tree = commit.tree().unwrap();
+        let entry = tree
+            .get_path(Path::new("gb-session/deltas/file.txt"))
+            .expect("session delta file should be folded into the snapshot tree");
+        let blob = entry.to_object(&repo).unwrap().into_blob().unwrap();
+        assert_eq!(blob.content(), r#"[{"timestamp":1}]"#.as_bytes());
+    }
 }
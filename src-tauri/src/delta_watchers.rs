@@ -1,24 +1,31 @@
 use crate::crdt::{Delta, TextDocument};
 use crate::projects::Project;
 use git2::{Commit, Repository};
-use notify::{Config, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use gitbutler_core::fs::Fs;
+use notify::event::ModifyKind;
+use notify::{EventKind, RecommendedWatcher};
 use serde::{Deserialize, Serialize};
 use std::path::{Path, PathBuf};
+use std::sync::mpsc::RecvTimeoutError;
 use std::thread;
-use std::{collections::HashMap, fs::File, sync::Mutex};
-use std::{io::Write, sync::mpsc::channel};
+use std::time::{Duration, Instant};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 use tauri::{Runtime, Window};
+use walkdir::WalkDir;
+
+/// How long a path must be quiet before its pending change is processed. Coalesces
+/// the burst of raw `notify` events an atomic save (remove+create+modify) produces
+/// into a single pass through the CRDT diff.
+const DEBOUNCE_WINDOW: Duration = Duration::from_millis(100);
 
 #[derive(Default)]
-pub struct WatcherCollection(Mutex<HashMap<String, RecommendedWatcher>>);
+pub struct WatcherCollection(Mutex<HashMap<String, Option<RecommendedWatcher>>>);
 
 pub fn unwatch(watchers: &WatcherCollection, project: Project) {
-    let mut watchers = watchers.0.lock().unwrap();
-    if let Some(mut watcher) = watchers.remove(&project.path) {
-        watcher
-            .unwatch(Path::new(&project.path))
-            .expect(format!("Failed to unwatch {}", &project.path).as_str());
-    }
+    // dropping the entry drops the underlying `notify` watcher (for `RealFs`), which
+    // stops the watch.
+    watchers.0.lock().unwrap().remove(&project.path);
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -28,63 +35,133 @@ struct DeltasEvent {
     deltas: Vec<Delta>,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+struct WatchErrorEvent {
+    project_id: String,
+    file_path: String,
+    message: String,
+}
+
+/// Everything that can go wrong diffing a single changed file. Kept per-file so that
+/// one unreadable file or a non-UTF8 blob doesn't tear down the whole watch thread.
+#[derive(Debug, thiserror::Error)]
+enum WatchError {
+    #[error("failed to read {path}: {source}")]
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("{path} is not valid UTF-8: {source}")]
+    NotUtf8 {
+        path: PathBuf,
+        source: std::string::FromUtf8Error,
+    },
+    #[error("git error while diffing {path}: {source}")]
+    Git { path: PathBuf, source: git2::Error },
+    #[error("{path} is not inside the project")]
+    StripPrefix { path: PathBuf },
+}
+
 pub fn watch<R: Runtime>(
     window: Window<R>,
     watchers: &WatcherCollection,
+    fs: Arc<dyn Fs>,
     project: Project,
 ) -> Result<(), String> {
     // Open the repository at this path
     let path = Path::new(&project.as_ref().path);
-    let repo = match Repository::open(path) {
-        Ok(repo) => repo,
-        Err(e) => panic!("failed to open: {}", e),
-    };
+    let repo = Repository::open(path).map_err(|e| format!("failed to open: {}", e))?;
 
-    let (tx, rx) = channel();
-    let mut watcher =
-        RecommendedWatcher::new(tx, Config::default()).expect("Failed to create watcher");
+    // catch up on anything that changed while GitButler wasn't running, before we
+    // start reacting to live events
+    for (relative_file_path, deltas) in scan(fs.as_ref(), &repo, &project) {
+        let event_name = format!("deltas://{}", project.id);
+        log::info!("Emitting event: {}", event_name);
+        window
+            .emit(
+                &event_name,
+                &DeltasEvent {
+                    deltas,
+                    project_id: project.id.clone(),
+                    file_path: relative_file_path.to_str().unwrap().to_string(),
+                },
+            )
+            .unwrap();
+    }
 
     log::info!("Watching {}", &project.path);
 
-    watcher
-        .watch(Path::new(&project.path), RecursiveMode::Recursive)
+    let handle = fs
+        .watch(Path::new(&project.path))
         .expect(format!("Failed to watch {}", &project.path).as_str());
 
     watchers
         .0
         .lock()
         .unwrap()
-        .insert(project.path.clone(), watcher);
+        .insert(project.path.clone(), handle.watcher);
+
+    let events = handle.events;
 
+    let thread_fs = fs.clone();
     thread::spawn(move || {
-        while let Ok(event) = rx.recv() {
-            if let Ok(event) = event {
-                for file_path in event.paths {
-                    match register_file_change(&repo, &project, &event.kind, &file_path) {
-                        Some(deltas) => {
-                            let relative_file_path = file_path
-                                .strip_prefix(&project.path)
-                                .unwrap()
-                                .to_str()
-                                .unwrap();
-                            let event_name = format!("deltas://{}", project.id);
-                            log::info!("Emitting event: {}", event_name);
-                            window
-                                .emit(
-                                    &event_name,
-                                    &DeltasEvent {
-                                        deltas,
-                                        project_id: project.id.clone(),
-                                        file_path: relative_file_path.to_string(),
-                                    },
-                                )
-                                .unwrap();
-                        }
-                        None => {}
+        // paths with a pending change, and the `notify` event kind that should be
+        // reported for them once the debounce window has elapsed
+        let mut pending: HashMap<PathBuf, (EventKind, Instant)> = HashMap::new();
+
+        loop {
+            match events.recv_timeout(DEBOUNCE_WINDOW) {
+                Ok(Ok(event)) => queue_event(thread_fs.as_ref(), &mut pending, event),
+                Ok(Err(e)) => log::error!("Error: {:?}", e),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+
+            let now = Instant::now();
+            let ready_paths: Vec<PathBuf> = pending
+                .iter()
+                .filter(|(_, (_, queued_at))| now.duration_since(*queued_at) >= DEBOUNCE_WINDOW)
+                .map(|(path, _)| path.clone())
+                .collect();
+
+            for file_path in ready_paths {
+                let (kind, _) = pending.remove(&file_path).unwrap();
+                match register_file_change(thread_fs.as_ref(), &repo, &project, &kind, &file_path) {
+                    Ok(Some(deltas)) => {
+                        let relative_file_path = file_path
+                            .strip_prefix(&project.path)
+                            .unwrap()
+                            .to_str()
+                            .unwrap();
+                        let event_name = format!("deltas://{}", project.id);
+                        log::info!("Emitting event: {}", event_name);
+                        window
+                            .emit(
+                                &event_name,
+                                &DeltasEvent {
+                                    deltas,
+                                    project_id: project.id.clone(),
+                                    file_path: relative_file_path.to_string(),
+                                },
+                            )
+                            .unwrap();
+                    }
+                    Ok(None) => {}
+                    Err(e) => {
+                        log::error!("{}", e);
+                        let event_name = format!("errors://{}", project.id);
+                        window
+                            .emit(
+                                &event_name,
+                                &WatchErrorEvent {
+                                    project_id: project.id.clone(),
+                                    file_path: file_path.to_str().unwrap_or_default().to_string(),
+                                    message: e.to_string(),
+                                },
+                            )
+                            .unwrap();
                     }
                 }
-            } else {
-                log::error!("Error: {:?}", event);
             }
         }
     });
@@ -92,28 +169,56 @@ pub fn watch<R: Runtime>(
     Ok(())
 }
 
+// Coalesces a raw `notify` event into the `pending` map, dropping metadata-only
+// noise and normalizing rename events into a remove-or-create of each path they name,
+// depending on whether that path still exists on disk.
+fn queue_event(fs: &dyn Fs, pending: &mut HashMap<PathBuf, (EventKind, Instant)>, event: notify::Event) {
+    if matches!(event.kind, EventKind::Access(_)) {
+        // metadata-only access, nothing changed on disk
+        return;
+    }
+
+    for file_path in event.paths {
+        let kind = if matches!(event.kind, EventKind::Modify(ModifyKind::Name(_))) {
+            if fs.exists(&file_path) {
+                EventKind::Create(notify::event::CreateKind::Any)
+            } else {
+                EventKind::Remove(notify::event::RemoveKind::Any)
+            }
+        } else {
+            event.kind.clone()
+        };
+        pending.insert(file_path, (kind, Instant::now()));
+    }
+}
+
 // this is what is called when the FS watcher detects a change
 // it should figure out delta data (crdt) and update the file at .git/gb/session/deltas/path/to/file
 // it also writes the metadata stuff which marks the beginning of a session if a session is not yet started
 // returns updated project deltas
 fn register_file_change(
+    fs: &dyn Fs,
     repo: &Repository,
     project: &Project,
     kind: &EventKind,
     file_path: &PathBuf,
-) -> Option<Vec<Delta>> {
+) -> Result<Option<Vec<Delta>>, WatchError> {
     // update meta files every time file change is detected
-    write_beginning_meta_files(&repo);
+    write_beginning_meta_files(fs, repo)?;
 
-    if !file_path.is_file() {
+    if !fs.is_file(file_path) {
         // only handle file changes
-        return None;
+        return Ok(None);
     }
 
-    let relative_file_path = Path::new(file_path.strip_prefix(&project.path).unwrap());
-    if repo.is_path_ignored(&relative_file_path).unwrap_or(true) {
+    let relative_file_path = file_path
+        .strip_prefix(&project.path)
+        .map_err(|_| WatchError::StripPrefix {
+            path: file_path.clone(),
+        })?;
+    if repo.is_path_ignored(relative_file_path).unwrap_or(true) {
         // make sure we're not watching ignored files
-        return None;
+        return Ok(None);
     }
 
     if EventKind::is_modify(&kind) {
@@ -125,88 +230,315 @@ fn register_file_change(
     }
 
     // first, we need to check if the file exists in the meta commit
-    let meta_commit = get_meta_commit(&repo);
-    let tree = meta_commit.tree().unwrap();
-    let commit_blob = if let Ok(object) = tree.get_path(Path::new(&relative_file_path)) {
-        // if file found, check if delta file exists
-        let blob = object.to_object(&repo).unwrap().into_blob().unwrap();
-        let contents = String::from_utf8(blob.content().to_vec()).unwrap();
-        Some(contents)
-    } else {
-        None
-    };
+    let meta_commit = get_meta_commit(repo).map_err(|source| WatchError::Git {
+        path: file_path.clone(),
+        source,
+    })?;
+    let tree = meta_commit.tree().map_err(|source| WatchError::Git {
+        path: file_path.clone(),
+        source,
+    })?;
+    let commit_blob = meta_blob(repo, &tree, relative_file_path)?;
 
     // second, get non-flushed file deltas
-    let deltas = project.get_file_deltas(Path::new(&relative_file_path));
+    let deltas = project.get_file_deltas(relative_file_path);
 
     // depending on the above, we can create TextDocument
-    let mut text_doc = match (commit_blob, deltas) {
-        (Some(contents), Some(deltas)) => TextDocument::new(&contents, deltas),
-        (Some(contents), None) => TextDocument::new(&contents, vec![]),
-        (None, Some(deltas)) => TextDocument::from_deltas(deltas),
-        (None, None) => TextDocument::from_deltas(vec![]),
-    };
+    let mut text_doc = text_document(commit_blob, deltas);
 
     // update the TextDocument with the new file contents
-    let contents = std::fs::read_to_string(file_path.clone())
-        .expect(format!("Failed to read {}", file_path.to_str().unwrap()).as_str());
+    let contents = fs
+        .read_to_string(file_path)
+        .map_err(|source| WatchError::Io {
+            path: file_path.clone(),
+            source,
+        })?;
 
     if !text_doc.update(&contents) {
-        return None;
+        return Ok(None);
     }
 
     // if the file was modified, save the deltas
     let deltas = text_doc.get_deltas();
     project.save_file_deltas(relative_file_path, &deltas);
-    return Some(deltas);
+    Ok(Some(deltas))
+}
+
+// decodes the blob recorded for `relative_file_path` in `tree`, if it's tracked
+// there. Shared by `register_file_change` and `scan` so a non-UTF8 blob is reported
+// as a `WatchError` rather than unwrapped in only one of the two call sites.
+fn meta_blob(
+    repo: &Repository,
+    tree: &git2::Tree,
+    relative_file_path: &Path,
+) -> Result<Option<String>, WatchError> {
+    let Ok(object) = tree.get_path(relative_file_path) else {
+        return Ok(None);
+    };
+    let blob = object
+        .to_object(repo)
+        .and_then(|object| {
+            object
+                .into_blob()
+                .map_err(|object| git2::Error::from_str(&format!("{} is not a blob", object.id())))
+        })
+        .map_err(|source| WatchError::Git {
+            path: relative_file_path.to_path_buf(),
+            source,
+        })?;
+    let contents =
+        String::from_utf8(blob.content().to_vec()).map_err(|source| WatchError::NotUtf8 {
+            path: relative_file_path.to_path_buf(),
+            source,
+        })?;
+    Ok(Some(contents))
+}
+
+// builds the TextDocument to diff against, from whatever baseline is available: the
+// blob recorded in the meta commit, any deltas already flushed for this file, both,
+// or neither (a brand new file)
+fn text_document(commit_blob: Option<String>, deltas: Option<Vec<Delta>>) -> TextDocument {
+    match (commit_blob, deltas) {
+        (Some(contents), Some(deltas)) => TextDocument::new(&contents, deltas),
+        (Some(contents), None) => TextDocument::new(&contents, vec![]),
+        (None, Some(deltas)) => TextDocument::from_deltas(deltas),
+        (None, None) => TextDocument::from_deltas(vec![]),
+    }
+}
+
+// walks the project tree and, for every tracked file whose on-disk contents differ
+// from the blob recorded in the meta commit, synthesizes the deltas needed to bring
+// a fresh TextDocument up to date. Called once when `watch` starts so a session
+// begins from an accurate baseline instead of only reacting to future notify events.
+fn scan(fs: &dyn Fs, repo: &Repository, project: &Project) -> Vec<(PathBuf, Vec<Delta>)> {
+    let mut results = Vec::new();
+    let meta_commit = match get_meta_commit(repo) {
+        Ok(meta_commit) => meta_commit,
+        Err(e) => {
+            log::error!("Failed to resolve meta commit for {}: {}", project.path, e);
+            return results;
+        }
+    };
+    let tree = match meta_commit.tree() {
+        Ok(tree) => tree,
+        Err(e) => {
+            log::error!("Failed to resolve meta commit tree for {}: {}", project.path, e);
+            return results;
+        }
+    };
+
+    for entry in WalkDir::new(&project.path)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+    {
+        let file_path = entry.path();
+        let relative_file_path = match file_path.strip_prefix(&project.path) {
+            Ok(relative_file_path) => relative_file_path,
+            Err(_) => continue,
+        };
+        if repo.is_path_ignored(relative_file_path).unwrap_or(true) {
+            continue;
+        }
+
+        let commit_blob = match meta_blob(repo, &tree, relative_file_path) {
+            Ok(commit_blob) => commit_blob,
+            Err(e) => {
+                log::error!("Failed to decode meta blob for {}: {}", file_path.display(), e);
+                continue;
+            }
+        };
+
+        let contents = match fs.read_to_string(file_path) {
+            Ok(contents) => contents,
+            Err(e) => {
+                log::error!("Failed to read {}: {}", file_path.display(), e);
+                continue;
+            }
+        };
+
+        if unchanged(&commit_blob, &contents) {
+            continue;
+        }
+
+        let deltas = project.get_file_deltas(relative_file_path);
+        let mut text_doc = text_document(commit_blob, deltas);
+        if !text_doc.update(&contents) {
+            continue;
+        }
+
+        let deltas = text_doc.get_deltas();
+        project.save_file_deltas(relative_file_path, &deltas);
+        results.push((relative_file_path.to_path_buf(), deltas));
+    }
+
+    results
+}
+
+// true if `contents` matches the blob recorded for this file in the meta commit, i.e.
+// there's nothing new to diff since that snapshot was taken
+fn unchanged(commit_blob: &Option<String>, contents: &str) -> bool {
+    commit_blob.as_deref() == Some(contents)
 }
 
 // get commit from refs/gitbutler/current or fall back to HEAD
-fn get_meta_commit(repo: &Repository) -> Commit {
+fn get_meta_commit(repo: &Repository) -> Result<Commit, git2::Error> {
     match repo.revparse_single("refs/gitbutler/current") {
-        Ok(object) => repo.find_commit(object.id()).unwrap(),
+        Ok(object) => repo.find_commit(object.id()),
         Err(_) => {
-            let head = repo.head().unwrap();
-            repo.find_commit(head.target().unwrap()).unwrap()
+            let head = repo.head()?;
+            repo.find_commit(head.target().ok_or_else(|| {
+                git2::Error::from_str("HEAD does not point at a commit")
+            })?)
         }
     }
 }
 
 // this function is called when the user modifies a file, it writes starting metadata if not there
 // and also touches the last activity timestamp, so we can tell when we are idle
-fn write_beginning_meta_files(repo: &Repository) {
+//
+// routed through `fs: &dyn Fs` and fallible like the rest of `register_file_change`, since a
+// brand-new repo with no commits yet (no branch/commit to record) is a normal, not exceptional,
+// state to hit here.
+fn write_beginning_meta_files(fs: &dyn Fs, repo: &Repository) -> Result<(), WatchError> {
     let meta_path = repo.path().join(Path::new("gb/session/meta"));
-    // create the parent directory recurisvely if it doesn't exist
-    std::fs::create_dir_all(meta_path.clone()).unwrap();
+    fs.create_dir_all(&meta_path)
+        .map_err(|source| WatchError::Io {
+            path: meta_path.clone(),
+            source,
+        })?;
 
     // check if the file .git/gb/meta/start exists and if not, write the current timestamp into it
     let meta_session_start = meta_path.join(Path::new("session-start"));
-    if !meta_session_start.exists() {
-        let mut file = File::create(meta_session_start).unwrap();
-        file.write_all(chrono::Local::now().timestamp().to_string().as_bytes())
-            .unwrap();
+    if !fs.exists(&meta_session_start) {
+        fs.write(
+            &meta_session_start,
+            &chrono::Local::now().timestamp().to_string(),
+        )
+        .map_err(|source| WatchError::Io {
+            path: meta_session_start.clone(),
+            source,
+        })?;
     }
 
     // check if the file .git/gb/session/meta/branch exists and if not, write the current branch name into it
     let meta_branch = meta_path.join(Path::new("branch"));
-    if !meta_branch.exists() {
-        let mut file = File::create(meta_branch).unwrap();
-        let branch = repo.head().unwrap();
-        let branch_name = branch.name().unwrap();
-        file.write_all(branch_name.as_bytes()).unwrap();
+    if !fs.exists(&meta_branch) {
+        let branch = repo.head().map_err(|source| WatchError::Git {
+            path: meta_branch.clone(),
+            source,
+        })?;
+        let branch_name = branch.name().ok_or_else(|| WatchError::Git {
+            path: meta_branch.clone(),
+            source: git2::Error::from_str("HEAD is not a valid UTF-8 branch name"),
+        })?;
+        fs.write(&meta_branch, branch_name)
+            .map_err(|source| WatchError::Io {
+                path: meta_branch.clone(),
+                source,
+            })?;
     }
 
     // check if the file .git/gb/session/meta/commit exists and if not, write the current commit hash into it
     let meta_commit = meta_path.join(Path::new("commit"));
-    if !meta_commit.exists() {
-        let mut file = File::create(meta_commit).unwrap();
-        let commit = repo.head().unwrap().peel_to_commit().unwrap();
-        file.write_all(commit.id().to_string().as_bytes()).unwrap();
+    if !fs.exists(&meta_commit) {
+        let commit = repo
+            .head()
+            .and_then(|head| head.peel_to_commit())
+            .map_err(|source| WatchError::Git {
+                path: meta_commit.clone(),
+                source,
+            })?;
+        fs.write(&meta_commit, &commit.id().to_string())
+            .map_err(|source| WatchError::Io {
+                path: meta_commit.clone(),
+                source,
+            })?;
     }
 
     // ALWAYS write the last time we did this
     let meta_session_last = meta_path.join(Path::new("session-last"));
-    let mut file = File::create(meta_session_last).unwrap();
-    file.write_all(chrono::Local::now().timestamp().to_string().as_bytes())
-        .unwrap();
+    fs.write(
+        &meta_session_last,
+        &chrono::Local::now().timestamp().to_string(),
+    )
+    .map_err(|source| WatchError::Io {
+        path: meta_session_last.clone(),
+        source,
+    })?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gitbutler_core::fs::FakeFs;
+    use notify::event::RenameMode;
+
+    #[test]
+    fn queue_event_coalesces_a_burst_into_one_pending_entry() {
+        let fs = FakeFs::new();
+        let mut pending = HashMap::new();
+        let path = PathBuf::from("/repo/src/main.rs");
+
+        for _ in 0..5 {
+            let event = notify::Event::new(EventKind::Modify(ModifyKind::Data(
+                notify::event::DataChange::Content,
+            )))
+            .add_path(path.clone());
+            queue_event(&fs, &mut pending, event);
+        }
+
+        assert_eq!(pending.len(), 1);
+        assert!(matches!(
+            pending.get(&path).unwrap().0,
+            EventKind::Modify(ModifyKind::Data(_))
+        ));
+    }
+
+    #[test]
+    fn queue_event_normalizes_rename_based_on_whether_the_path_still_exists() {
+        let fs = FakeFs::new();
+        let mut pending = HashMap::new();
+        let renamed_path = PathBuf::from("/repo/renamed.txt");
+
+        let rename_event = |path: &PathBuf| {
+            notify::Event::new(EventKind::Modify(ModifyKind::Name(RenameMode::Any))).add_path(path.clone())
+        };
+
+        // the old name of a rename: the new path isn't on disk under the old name
+        queue_event(&fs, &mut pending, rename_event(&renamed_path));
+        assert!(matches!(
+            pending.get(&renamed_path).unwrap().0,
+            EventKind::Remove(_)
+        ));
+
+        // the new name of a rename: now it exists
+        fs.insert_file(renamed_path.clone(), "contents");
+        queue_event(&fs, &mut pending, rename_event(&renamed_path));
+        assert!(matches!(
+            pending.get(&renamed_path).unwrap().0,
+            EventKind::Create(_)
+        ));
+    }
+
+    #[test]
+    fn queue_event_drops_access_only_events() {
+        let fs = FakeFs::new();
+        let mut pending = HashMap::new();
+        let event = notify::Event::new(EventKind::Access(notify::event::AccessKind::Read))
+            .add_path(PathBuf::from("/repo/src/main.rs"));
+
+        queue_event(&fs, &mut pending, event);
+
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn unchanged_detects_identical_contents() {
+        assert!(unchanged(&Some("fn main() {}".to_string()), "fn main() {}"));
+        assert!(!unchanged(&Some("fn main() {}".to_string()), "fn main() {} "));
+        assert!(!unchanged(&None, "new file"));
+    }
 }
@@ -1,38 +1,90 @@
-use std::{fs, path::PathBuf};
+use std::{path::PathBuf, sync::Arc};
+
+use gitbutler_core::fs::Fs;
+use gitbutler_core::lock::FileLock;
 use tauri::PathResolver;
 
-#[derive(Default)]
+#[derive(Clone)]
 pub struct Storage {
     local_data_dir: PathBuf,
+    fs: Arc<dyn Fs>,
 }
 
 impl Storage {
-    pub fn new(resolver: &PathResolver) -> Self {
+    pub fn new(resolver: &PathResolver, fs: Arc<dyn Fs>) -> Self {
         log::info!(
             "Local data dir: {:?}",
             resolver.app_local_data_dir().unwrap()
         );
         Self {
             local_data_dir: resolver.app_local_data_dir().unwrap(),
+            fs,
         }
     }
 
     pub fn read(&self, path: &str) -> Result<Option<String>, String> {
         let file_path = self.local_data_dir.join(path);
-        if !file_path.exists() {
+        if !self.fs.exists(&file_path) {
             return Ok(None);
         }
-        let contents = fs::read_to_string(file_path).expect("Unable to read file");
+        let contents = self.fs.read_to_string(&file_path).expect("Unable to read file");
         Ok(Some(contents))
     }
 
     pub fn write(&self, path: &str, content: &str) -> Result<(), String> {
         let file_path = self.local_data_dir.join(path);
         let dir = file_path.parent().unwrap();
-        if !dir.exists() {
-            fs::create_dir_all(dir).unwrap();
+        if !self.fs.exists(dir) {
+            self.fs.create_dir_all(dir).unwrap();
         }
-        fs::write(file_path, content).expect("Unable to write file");
+        // hold an advisory lock for the write so two windows/processes racing on the
+        // same file can't interleave; `Fs::write` itself writes-then-renames so
+        // readers never see a half-written file in between.
+        let _lock = FileLock::acquire(self.fs.clone(), &file_path).map_err(|e| e.to_string())?;
+        self.fs.write(&file_path, content).expect("Unable to write file");
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gitbutler_core::fs::FakeFs;
+
+    // `tauri::PathResolver` can't be constructed outside a running app, so tests build
+    // `Storage` directly with a `local_data_dir` of their own rather than going through
+    // `Storage::new`.
+    fn test_storage() -> Storage {
+        Storage {
+            local_data_dir: PathBuf::from("/data"),
+            fs: Arc::new(FakeFs::new()),
+        }
+    }
+
+    #[test]
+    fn read_returns_none_for_a_missing_file() {
+        let storage = test_storage();
+        assert_eq!(storage.read("config.toml").unwrap(), None);
+    }
+
+    #[test]
+    fn write_then_read_round_trips_and_creates_parent_dirs() {
+        let storage = test_storage();
+        storage.write("nested/config.toml", "key = 1").unwrap();
+        assert_eq!(
+            storage.read("nested/config.toml").unwrap(),
+            Some("key = 1".to_string())
+        );
+    }
+
+    #[test]
+    fn write_overwrites_an_existing_file() {
+        let storage = test_storage();
+        storage.write("config.toml", "key = 1").unwrap();
+        storage.write("config.toml", "key = 2").unwrap();
+        assert_eq!(
+            storage.read("config.toml").unwrap(),
+            Some("key = 2".to_string())
+        );
+    }
+}